@@ -51,6 +51,38 @@ pub enum Instruction {
 
     /// Send a message with the given name; stack should contain [pid, value].
     Send(&'static str),
+
+    /// Install an exception handler for the current frame, whose catch block
+    /// begins at the given instruction in this function.
+    PushTry(usize),
+
+    /// Remove the most recently installed exception handler.
+    PopTry,
+
+    /// Pop the exception value off the stack and unwind to the nearest handler,
+    /// terminating the process if there is none.
+    Throw,
+
+    /// Like `Receive`, but wait at most the given number of milliseconds.  If a
+    /// message arrives, push its name and value as `Receive` does and fall
+    /// through; if the timeout elapses first, push nothing and jump to the
+    /// given instruction so the program can run its after-branch.
+    ReceiveTimeout(u64, usize),
+
+    /// Like `Receive`, but only accept a message whose name is in the given
+    /// set.  Messages that do not match are buffered for a later `Receive` or
+    /// `ReceiveMatching`, preserving their arrival order.
+    ReceiveMatching(&'static [&'static str]),
+
+    /// Bidirectionally link the current process to the pid on top of the stack,
+    /// which is consumed.  When either one ends, the other receives an `"EXIT"`
+    /// message if it traps exits, or is taken down if the exit was abnormal.
+    Link,
+
+    /// Mark the current process as trapping exits: instead of being taken down
+    /// when a linked process ends, it receives an `"EXIT"` message naming the
+    /// dead pid.
+    TrapExit,
 }
 
 /// A collection of instructions that can be executed.  When begun, the stack
@@ -91,19 +123,71 @@ impl Function {
     }
 }
 
+/// A host-side function, implemented in Rust and invoked synchronously by
+/// `Call`.  It consumes `arg_count` values from the stack and leaves a single
+/// return value.
+#[derive(Clone)]
+pub struct NativeFunction {
+    arg_count: usize,
+    f: Arc<dyn Fn(Vec<Value>) -> Value + Send + Sync>,
+}
+
+impl NativeFunction {
+    pub fn arg_count(&self) -> usize {
+        self.arg_count
+    }
+
+    pub fn call(&self, args: Vec<Value>) -> Value {
+        (self.f)(args)
+    }
+}
+
+/// Something a `Call` can resolve to: either a bytecode `Function` or a
+/// host-provided `NativeFunction`.
+#[derive(Clone)]
+pub enum Callable {
+    Function(Function),
+    Native(NativeFunction),
+}
+
 /// A module represents a set of named functions.
 #[derive(Default)]
 pub struct Module {
-    pub functions: HashMap<String, Function>,
+    pub functions: HashMap<String, Callable>,
 }
 
 impl Module {
-    /// Add a new function to this module.
+    /// Add a new bytecode function to this module.
     pub fn add_function<S: Into<String>>(&mut self, name: S, function: Function) {
-        self.functions.insert(name.into(), function);
+        self.functions
+            .insert(name.into(), Callable::Function(function));
+    }
+
+    /// Add a new native function to this module.
+    pub fn add_native<S, F>(&mut self, name: S, arg_count: usize, f: F)
+    where
+        S: Into<String>,
+        F: Fn(Vec<Value>) -> Value + Send + Sync + 'static,
+    {
+        self.functions.insert(
+            name.into(),
+            Callable::Native(NativeFunction {
+                arg_count,
+                f: Arc::new(f),
+            }),
+        );
+    }
+
+    /// Get the callable with the given name, whether native or bytecode.
+    pub fn get_callable<S: AsRef<str>>(&self, name: S) -> Option<Callable> {
+        self.functions.get(name.as_ref()).cloned()
     }
 
+    /// Get the bytecode function with the given name, if it is one.
     pub fn get_function<S: AsRef<str>>(&self, name: S) -> Option<Function> {
-        self.functions.get(name.as_ref()).map(|f| f.clone())
+        match self.functions.get(name.as_ref()) {
+            Some(Callable::Function(f)) => Some(f.clone()),
+            _ => None,
+        }
     }
 }
@@ -1,14 +1,50 @@
 //! Stack-based VM
 
 use crate::data::{Message, Value};
-use crate::program::{Function, Instruction, Module};
-use std::collections::HashMap;
+use crate::program::{Callable, Function, Instruction, Module};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::{
     mpsc::{channel, Receiver, Sender},
     Notify,
 };
 
+/// Why a process ended.  A normal `Return` does not take down non-trapping
+/// linked processes; anything else (an uncaught exception, or being killed)
+/// does.
+enum ExitReason {
+    Normal,
+    Abnormal(Value),
+}
+
+/// A cooperative cancellation signal for a single process: `flag` is checked
+/// at reduction boundaries, and `notify` wakes a process that is currently
+/// blocked in a receive, so a kill is noticed promptly either way.
+struct Kill {
+    flag: AtomicBool,
+    notify: Notify,
+}
+
+impl Kill {
+    fn new() -> Self {
+        Kill {
+            flag: AtomicBool::new(false),
+            notify: Notify::new(),
+        }
+    }
+
+    fn set(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+        self.notify.notify_one();
+    }
+
+    fn is_set(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+}
+
 #[derive(Clone)]
 pub struct VM(Arc<Mutex<VMInner>>);
 
@@ -21,6 +57,18 @@ pub struct VMInner {
     /// channels to communicate with each process
     channels: HashMap<u64, Sender<Message>>,
 
+    /// per-process cancellation flags, set to ask a process to stop
+    kills: HashMap<u64, Arc<Kill>>,
+
+    /// bidirectional links between processes
+    links: HashMap<u64, HashSet<u64>>,
+
+    /// processes that trap exits rather than being taken down with a link
+    trapping: HashSet<u64>,
+
+    /// maximum depth of a process's call stack before it overflows
+    max_call_depth: usize,
+
     /// signalled when a process has exited
     process_exited: Arc<Notify>,
 }
@@ -31,13 +79,26 @@ impl VM {
             module,
             next_pid: 0,
             channels: HashMap::new(),
+            kills: HashMap::new(),
+            links: HashMap::new(),
+            trapping: HashSet::new(),
+            max_call_depth: 10_000,
             process_exited: Arc::new(Notify::new()),
         })))
     }
 
+    /// Set the maximum call-stack depth a process may reach before it raises a
+    /// `"stack_overflow"` error.
+    #[allow(dead_code)] // embedder API, not exercised by this crate's own code
+    pub fn with_max_call_depth(self, max_call_depth: usize) -> VM {
+        self.0.lock().unwrap().max_call_depth = max_call_depth;
+        self
+    }
+
     fn spawn_process<S: Into<String>>(&self, function_name: S, args: Vec<Value>) -> u64 {
         let receiver;
         let pid;
+        let kill;
         {
             let mut inner = self.0.lock().unwrap();
             pid = inner.next_pid;
@@ -47,23 +108,87 @@ impl VM {
             let chan = channel(10);
             inner.channels.insert(pid, chan.0);
             receiver = chan.1;
+
+            kill = Arc::new(Kill::new());
+            inner.kills.insert(pid, kill.clone());
         }
         tokio::spawn(
             self.clone()
-                .run_process(function_name.into(), args, pid, receiver),
+                .run_process(function_name.into(), args, pid, receiver, kill),
         );
         pid
     }
 
+    /// Ask the given process to stop at its next reduction boundary, or when it
+    /// is (or next is) blocked waiting for a message, whichever comes first.
+    /// Does nothing if there is no such process.
+    #[allow(dead_code)] // embedder API, not exercised by this crate's own code
+    pub fn kill(&self, pid: u64) {
+        let inner = self.0.lock().unwrap();
+        if let Some(kill) = inner.kills.get(&pid) {
+            kill.set();
+        }
+    }
+
+    /// Handle the termination of a process: forget its channel, then notify its
+    /// linked processes and drop all of its links.  Trapping processes are sent
+    /// an `"EXIT"` message naming the dead pid (dropped if their mailbox is
+    /// full); non-trapping linked processes are only taken down if `reason` is
+    /// abnormal -- a normal `Return` leaves them running.
+    fn terminate_process(&self, pid: u64, reason: ExitReason) {
+        if let ExitReason::Abnormal(ref value) = reason {
+            println!("[{}] exited abnormally: {:?}", pid, value);
+        }
+        let mut inner = self.0.lock().unwrap();
+        inner.channels.remove(&pid);
+        inner.kills.remove(&pid);
+        inner.trapping.remove(&pid);
+        let linked = inner.links.remove(&pid).unwrap_or_default();
+        for other in linked {
+            // drop the reverse half of the link
+            if let Some(set) = inner.links.get_mut(&other) {
+                set.remove(&pid);
+            }
+            // skip processes that have already ended
+            if !inner.channels.contains_key(&other) {
+                continue;
+            }
+            if inner.trapping.contains(&other) {
+                let message = Message {
+                    name: "EXIT",
+                    value: Value::Pid(pid),
+                };
+                // best-effort: a full mailbox simply drops the signal
+                let _ = inner.channels[&other].try_send(message);
+            } else if matches!(reason, ExitReason::Abnormal(_)) {
+                // ask the linked process to stop; it reaches its own
+                // terminate_process (and so cascades further) once it
+                // notices, either at a reduction boundary or by waking from
+                // a blocked receive. We never touch its channel here -- only
+                // the process itself retires its own bookkeeping.
+                if let Some(kill) = inner.kills.get(&other) {
+                    kill.set();
+                }
+            }
+        }
+        inner.process_exited.notify_one();
+    }
+
     async fn run_process(
         self,
         function_name: String,
         args: Vec<Value>,
         pid: u64,
         mut receiver: Receiver<Message>,
+        kill: Arc<Kill>,
     ) {
         use Instruction::*;
 
+        // number of instructions to execute before yielding to the scheduler
+        // and checking for cancellation
+        const REDUCTION_BUDGET: u32 = 2000;
+        let mut reductions: u32 = 0;
+
         let mut function;
         {
             let inner = self.0.lock().unwrap();
@@ -73,14 +198,29 @@ impl VM {
                 .expect("no function with that name");
         }
 
+        // a pending exception handler: when an exception is thrown, the stack
+        // is truncated back to `stack_len` within the frame at `frame_depth`
+        // and execution resumes at `catch_instr`.
+        struct TryFrame {
+            catch_instr: usize,
+            stack_len: usize,
+            frame_depth: usize,
+        }
+
         // the call stack contains all but the current frame, which is
         // broken out as local variables
         struct Frame {
             function: Function,
             next_instr: usize,
             stack: Vec<Value>,
+            try_frames: Vec<TryFrame>,
         }
-        let mut frame_stack = vec![];
+        let mut frame_stack: Vec<Frame> = vec![];
+        let mut try_frames: Vec<TryFrame> = vec![];
+
+        // messages that arrived but were not accepted by a `ReceiveMatching`,
+        // held in arrival order until a later receive consumes them
+        let mut pending: Vec<Message> = vec![];
 
         // ensure we got the number of args expected
         debug_assert_eq!(function.arg_count(), args.len());
@@ -91,8 +231,71 @@ impl VM {
         let mut stack = args;
         stack.reserve(stack_size);
 
-        // TODO: tokio::task::yield_now().await sometimes?
+        // unwind to the nearest try frame, resuming in its catch block with the
+        // given exception value on the stack.  If no handler exists in the
+        // current frame or any of its parents, the process terminates cleanly.
+        macro_rules! throw {
+            ($exc:expr) => {{
+                let exc = $exc;
+                'unwind: loop {
+                    if let Some(tf) = try_frames.pop() {
+                        debug_assert_eq!(tf.frame_depth, frame_stack.len());
+                        debug_assert!(stack.len() >= tf.stack_len);
+                        stack.truncate(tf.stack_len);
+                        stack.push(exc);
+                        next_instr = tf.catch_instr;
+                        break 'unwind;
+                    }
+                    if let Some(parent) = frame_stack.pop() {
+                        function = parent.function;
+                        instructions = function.instructions();
+                        stack_size = function.stack_size();
+                        stack = parent.stack;
+                        try_frames = parent.try_frames;
+                    } else {
+                        // the exception escaped the process; end it cleanly
+                        self.terminate_process(pid, ExitReason::Abnormal(exc));
+                        return;
+                    }
+                }
+                continue
+            }};
+        }
+
+        // wait for the next message, terminating cleanly (rather than
+        // panicking) if the process is killed while blocked here, or if the
+        // channel unexpectedly closes
+        macro_rules! recv_or_die {
+            () => {{
+                tokio::select! {
+                    m = receiver.recv() => match m {
+                        Some(m) => m,
+                        None => {
+                            self.terminate_process(pid, ExitReason::Abnormal(Value::Str("killed")));
+                            return;
+                        }
+                    },
+                    _ = kill.notify.notified() => {
+                        self.terminate_process(pid, ExitReason::Abnormal(Value::Str("killed")));
+                        return;
+                    }
+                }
+            }};
+        }
+
         loop {
+            // periodically yield so other processes make progress, and honour a
+            // cancellation request by ending the process abnormally ("killed")
+            reductions += 1;
+            if reductions >= REDUCTION_BUDGET {
+                reductions = 0;
+                tokio::task::yield_now().await;
+                if kill.is_set() {
+                    self.terminate_process(pid, ExitReason::Abnormal(Value::Str("killed")));
+                    return;
+                }
+            }
+
             let instr = &instructions[next_instr];
             println!("[{}] Execute {:?} with stack {:?}", pid, instr, stack);
             next_instr += 1;
@@ -107,8 +310,9 @@ impl VM {
                     stack.push((*v).clone());
                 }
                 Pop => {
-                    debug_assert!(!stack.is_empty());
-                    stack.pop().unwrap();
+                    if stack.pop().is_none() {
+                        throw!(Value::Str("Pop on empty stack"));
+                    }
                 }
                 Swap(i) => {
                     debug_assert!(stack.len() > i);
@@ -132,14 +336,34 @@ impl VM {
                     }
                 }
                 Call(name) => {
-                    let child_function;
+                    let callable;
+                    let max_call_depth;
                     {
                         let inner = self.0.lock().unwrap();
-                        child_function = inner
+                        callable = inner
                             .module
-                            .get_function(name)
+                            .get_callable(name)
                             .expect("no function with that name");
+                        max_call_depth = inner.max_call_depth;
+                    }
+
+                    // native functions run synchronously without a call frame
+                    let child_function = match callable {
+                        Callable::Function(f) => f,
+                        Callable::Native(nf) => {
+                            let arg_count = nf.arg_count();
+                            debug_assert!(stack.len() >= arg_count);
+                            let args = stack.split_off(stack.len() - arg_count);
+                            stack.push(nf.call(args));
+                            continue;
+                        }
+                    };
+
+                    // guard against unbounded recursion growing the call stack
+                    if frame_stack.len() >= max_call_depth {
+                        throw!(Value::Str("stack_overflow"));
                     }
+
                     let arg_count = child_function.arg_count();
                     debug_assert!(stack.len() >= arg_count);
                     let child_stack = stack.split_off(stack.len() - arg_count);
@@ -149,6 +373,7 @@ impl VM {
                         function,
                         next_instr,
                         stack,
+                        try_frames,
                     });
 
                     // and set the local variables to point to the new child frame
@@ -157,6 +382,7 @@ impl VM {
                     stack_size = function.stack_size();
                     next_instr = 0;
                     stack = child_stack;
+                    try_frames = vec![];
                 }
                 Return => {
                     let mut parent = if let Some(frame) = frame_stack.pop() {
@@ -164,11 +390,7 @@ impl VM {
                     } else {
                         // return from top-level frame exits the process
                         debug_assert_eq!(stack.len(), 0);
-                        {
-                            let mut inner = self.0.lock().unwrap();
-                            inner.channels.remove(&pid);
-                            inner.process_exited.notify_one();
-                        }
+                        self.terminate_process(pid, ExitReason::Normal);
                         return;
                     };
                     parent.stack.append(&mut stack);
@@ -178,24 +400,41 @@ impl VM {
                     stack_size = function.stack_size();
                     next_instr = parent.next_instr;
                     stack = parent.stack;
+                    try_frames = parent.try_frames;
+                }
+                PushTry(dest) => {
+                    try_frames.push(TryFrame {
+                        catch_instr: dest,
+                        stack_len: stack.len(),
+                        frame_depth: frame_stack.len(),
+                    });
+                }
+                PopTry => {
+                    debug_assert!(!try_frames.is_empty());
+                    try_frames.pop();
+                }
+                Throw => {
+                    debug_assert!(!stack.is_empty());
+                    let exc = stack.pop().unwrap();
+                    throw!(exc);
                 }
                 Add => {
                     debug_assert!(stack.len() >= 2);
                     let a = stack.pop().unwrap();
                     let b = stack.pop().unwrap();
-                    stack.push(match (a, b) {
-                        (Value::Int(a), Value::Int(b)) => Value::Int(a + b),
-                        _ => panic!("Add only supports ints"),
-                    })
+                    match (a, b) {
+                        (Value::Int(a), Value::Int(b)) => stack.push(Value::Int(a + b)),
+                        _ => throw!(Value::Str("Add only supports ints")),
+                    }
                 }
                 Mul => {
                     debug_assert!(stack.len() >= 2);
                     let a = stack.pop().unwrap();
                     let b = stack.pop().unwrap();
-                    stack.push(match (a, b) {
-                        (Value::Int(a), Value::Int(b)) => Value::Int(a * b),
-                        _ => panic!("Mul only supports ints"),
-                    })
+                    match (a, b) {
+                        (Value::Int(a), Value::Int(b)) => stack.push(Value::Int(a * b)),
+                        _ => throw!(Value::Str("Mul only supports ints")),
+                    }
                 }
                 Spawn(name) => {
                     let arg_count = {
@@ -211,26 +450,99 @@ impl VM {
                     let pid = self.spawn_process(name, child_stack);
                     stack.push(Value::Pid(pid));
                 }
+                Link => {
+                    debug_assert!(!stack.is_empty());
+                    let other = if let Value::Pid(other) = stack.pop().unwrap() {
+                        other
+                    } else {
+                        throw!(Value::Str("value on stack is not a Pid"));
+                    };
+                    let mut inner = self.0.lock().unwrap();
+                    inner.links.entry(pid).or_default().insert(other);
+                    inner.links.entry(other).or_default().insert(pid);
+                }
+                TrapExit => {
+                    self.0.lock().unwrap().trapping.insert(pid);
+                }
                 Receive => {
-                    let msg = receiver.recv().await.expect("channel closed unexpectedly");
+                    // take a buffered message first so ordering stays consistent
+                    let msg = if pending.is_empty() {
+                        recv_or_die!()
+                    } else {
+                        pending.remove(0)
+                    };
+                    stack.push(Value::Str(msg.name));
+                    stack.push(msg.value);
+                }
+                ReceiveMatching(names) => {
+                    // scan the buffer for an already-queued match
+                    let mut msg = None;
+                    if let Some(i) = pending.iter().position(|m| names.contains(&m.name)) {
+                        msg = Some(pending.remove(i));
+                    }
+                    // otherwise wait for one, buffering anything that doesn't match
+                    let msg = loop {
+                        if let Some(msg) = msg {
+                            break msg;
+                        }
+                        let m = recv_or_die!();
+                        if names.contains(&m.name) {
+                            break m;
+                        }
+                        pending.push(m);
+                    };
                     stack.push(Value::Str(msg.name));
                     stack.push(msg.value);
                 }
+                ReceiveTimeout(ms, dest) => {
+                    // take a buffered message first so ordering stays consistent
+                    let msg = if pending.is_empty() {
+                        tokio::select! {
+                            m = tokio::time::timeout(Duration::from_millis(ms), receiver.recv()) => {
+                                match m {
+                                    Ok(Some(m)) => Some(m),
+                                    Ok(None) => {
+                                        self.terminate_process(pid, ExitReason::Abnormal(Value::Str("killed")));
+                                        return;
+                                    }
+                                    Err(_) => None,
+                                }
+                            }
+                            _ = kill.notify.notified() => {
+                                self.terminate_process(pid, ExitReason::Abnormal(Value::Str("killed")));
+                                return;
+                            }
+                        }
+                    } else {
+                        Some(pending.remove(0))
+                    };
+                    match msg {
+                        Some(msg) => {
+                            stack.push(Value::Str(msg.name));
+                            stack.push(msg.value);
+                        }
+                        None => next_instr = dest,
+                    }
+                }
                 Send(name) => {
                     debug_assert!(stack.len() >= 2);
                     let value = stack.pop().unwrap();
                     let pid = if let Value::Pid(pid) = stack.pop().unwrap() {
                         pid
                     } else {
-                        panic!("value on stack is not a Pid")
+                        throw!(Value::Str("value on stack is not a Pid"));
                     };
                     let message = Message { name, value };
 
                     let sender = {
                         let inner = self.0.lock().unwrap();
-                        inner.channels.get(&pid).expect("no such pid").clone()
+                        inner.channels.get(&pid).cloned()
+                    };
+                    let sender = match sender {
+                        Some(sender) => sender,
+                        None => throw!(Value::Str("no such pid")),
                     };
-                    if let Err(_) = sender.send(message).await {
+                    if sender.send(message).await.is_err() {
                         // XXX can't .unwrap() this
                         panic!("uhoh");
                     }